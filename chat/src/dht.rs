@@ -0,0 +1,297 @@
+//! A Kademlia-style peer directory so chat server instances can federate
+//! without a central hub.
+//!
+//! Each node's 160-bit ID is the SHA-1 hash of its federation address (see
+//! `node_id`), and each node organizes known peers into
+//! k-buckets indexed by the position of the highest-order set bit of the XOR
+//! distance between our ID and theirs (bucket `i` holds peers whose distance
+//! falls in `[2^i, 2^(i+1))`). Users are mapped to a key by hashing their
+//! `user_id`; the address of the server currently holding a user is stored
+//! under the nodes closest to that key, so the relay can fall back to a DHT
+//! lookup when a `to` user is not in the local `SESSIONS`.
+//!
+//! The XOR metric and the step-bounded iterative lookup follow OpenEthereum's
+//! network host discovery. The routing table keeps one bucket per id bit
+//! (`ID_BITS` buckets, indices `0..ID_BITS`).
+
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+/// Width of a node/key id in bits and bytes.
+pub const ID_BITS: usize = 160;
+pub const ID_BYTES: usize = ID_BITS / 8;
+/// Maximum entries per bucket.
+pub const K: usize = 16;
+/// Lookup concurrency (closest peers queried per step).
+pub const ALPHA: usize = 3;
+/// Upper bound on iterative-lookup rounds, mirroring `DISCOVERY_MAX_STEPS`.
+pub const DISCOVERY_MAX_STEPS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct NodeId(pub [u8; ID_BYTES]);
+
+impl NodeId {
+    /// The XOR distance to `other`, comparable lexicographically (closer first).
+    pub fn distance(&self, other: &NodeId) -> [u8; ID_BYTES] {
+        let mut d = [0u8; ID_BYTES];
+        for (d, (a, b)) in d.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            *d = a ^ b;
+        }
+        d
+    }
+
+    /// Index of the bucket holding `other`: the position (counting from the
+    /// least-significant bit) of the highest set bit of the XOR distance, so a
+    /// distance in `[2^i, 2^(i+1))` lands in bucket `i`. `None` when the ids are
+    /// identical (distance zero).
+    pub fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let d = self.distance(other);
+        for (byte_pos, &byte) in d.iter().enumerate() {
+            if byte != 0 {
+                let high_bit = 7 - byte.leading_zeros() as usize;
+                return Some((ID_BYTES - 1 - byte_pos) * 8 + high_bit);
+            }
+        }
+        None
+    }
+}
+
+/// Hash a `user_id` into the 160-bit key space.
+pub fn user_key(user_id: u16) -> NodeId {
+    let digest = Sha1::digest(user_id.to_be_bytes());
+    let mut id = [0u8; ID_BYTES];
+    id.copy_from_slice(&digest);
+    NodeId(id)
+}
+
+/// Derive a node's id from its federation address, so every server agrees on
+/// the id of a peer given only its address.
+pub fn node_id(addr: &str) -> NodeId {
+    let digest = Sha1::digest(addr.as_bytes());
+    let mut id = [0u8; ID_BYTES];
+    id.copy_from_slice(&digest);
+    NodeId(id)
+}
+
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+/// Asks a peer whether it is still alive, used when a full bucket must decide
+/// whether to evict its least-recently-seen entry.
+pub trait Pinger {
+    fn ping(&self, peer: &Peer) -> bool;
+}
+
+/// The FIND_NODE RPC: ask `peer` for the peers it knows closest to `target`.
+pub trait DhtRpc {
+    fn find_node(&self, peer: &Peer, target: &NodeId) -> Vec<Peer>;
+}
+
+/// A routing table of `ID_BITS` k-buckets. Within each bucket the
+/// least-recently-seen peer sits at the front and the most-recently-seen at the
+/// back.
+#[derive(Clone)]
+pub struct RoutingTable {
+    local_id: NodeId,
+    buckets: Vec<Vec<Peer>>,
+}
+
+impl RoutingTable {
+    pub fn new(local_id: NodeId) -> Self {
+        Self {
+            local_id,
+            buckets: (0..ID_BITS).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Record contact with `peer`. A known peer is refreshed to most-recently
+    /// -seen; a new peer is appended if the bucket has room, otherwise the
+    /// least-recently-seen entry is evicted only if it fails to answer a ping.
+    pub fn update<P: Pinger>(&mut self, peer: Peer, pinger: &P) {
+        let idx = match self.local_id.bucket_index(&peer.id) {
+            Some(idx) => idx,
+            None => return, // never store ourselves
+        };
+        let bucket = &mut self.buckets[idx];
+
+        if let Some(pos) = bucket.iter().position(|p| p.id == peer.id) {
+            let existing = bucket.remove(pos);
+            bucket.push(existing);
+            return;
+        }
+
+        if bucket.len() < K {
+            bucket.push(peer);
+            return;
+        }
+
+        let lru = bucket[0].clone();
+        if pinger.ping(&lru) {
+            // Still alive: keep it (refreshed) and drop the newcomer.
+            let refreshed = bucket.remove(0);
+            bucket.push(refreshed);
+        } else {
+            bucket.remove(0);
+            bucket.push(peer);
+        }
+    }
+
+    /// The `count` known peers closest to `target` by XOR distance.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Peer> {
+        let mut all: Vec<Peer> = self.buckets.iter().flatten().cloned().collect();
+        all.sort_by_key(|p| p.id.distance(target));
+        all.truncate(count);
+        all
+    }
+}
+
+/// Iterative FIND_NODE lookup: keep a shortlist of the closest known peers,
+/// query the `ALPHA` closest not-yet-queried in each round, merge their answers
+/// sorted by XOR distance, and stop once a round learns no closer node or after
+/// `DISCOVERY_MAX_STEPS` rounds.
+pub fn lookup<R: DhtRpc>(table: &RoutingTable, rpc: &R, target: NodeId) -> Vec<Peer> {
+    let mut shortlist = table.closest(&target, K);
+    let mut queried: HashSet<NodeId> = HashSet::new();
+    let mut closest_seen = shortlist.first().map(|p| p.id.distance(&target));
+
+    for _ in 0..DISCOVERY_MAX_STEPS {
+        let to_query: Vec<Peer> = shortlist
+            .iter()
+            .filter(|p| !queried.contains(&p.id))
+            .take(ALPHA)
+            .cloned()
+            .collect();
+        if to_query.is_empty() {
+            break;
+        }
+
+        for peer in to_query {
+            queried.insert(peer.id);
+            for learned in rpc.find_node(&peer, &target) {
+                if !shortlist.iter().any(|p| p.id == learned.id) {
+                    shortlist.push(learned);
+                }
+            }
+        }
+
+        shortlist.sort_by_key(|p| p.id.distance(&target));
+        shortlist.truncate(K);
+
+        let new_closest = shortlist.first().map(|p| p.id.distance(&target));
+        if new_closest < closest_seen {
+            closest_seen = new_closest;
+        } else {
+            break; // no closer node learned this round
+        }
+    }
+
+    shortlist
+}
+
+/// Maps users (keyed by `user_key`) to the address of the server currently
+/// holding them. This is a local map only: `owner_of` answers from it and does
+/// not consult the routing table or any other node.
+pub struct Directory {
+    owners: HashMap<NodeId, String>,
+}
+
+impl Directory {
+    pub fn new() -> Self {
+        Self {
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Record that `server_addr` currently owns `user_id`.
+    pub fn announce(&mut self, user_id: u16, server_addr: String) {
+        self.owners.insert(user_key(user_id), server_addr);
+    }
+
+    pub fn forget(&mut self, user_id: u16) {
+        self.owners.remove(&user_key(user_id));
+    }
+
+    /// The address of the server owning `user_id`, if known locally.
+    pub fn owner_of(&self, user_id: u16) -> Option<String> {
+        self.owners.get(&user_key(user_id)).cloned()
+    }
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> NodeId {
+        let mut raw = [0u8; ID_BYTES];
+        raw[ID_BYTES - 1] = byte;
+        NodeId(raw)
+    }
+
+    struct AlwaysAlive;
+    impl Pinger for AlwaysAlive {
+        fn ping(&self, _peer: &Peer) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn bucket_index_tracks_highest_differing_bit() {
+        let local = id(0b0000);
+        // distance 1 -> bit 0, distance 2/3 -> bit 1, distance 4..7 -> bit 2.
+        assert_eq!(local.bucket_index(&id(0b0001)), Some(0));
+        assert_eq!(local.bucket_index(&id(0b0010)), Some(1));
+        assert_eq!(local.bucket_index(&id(0b0011)), Some(1));
+        assert_eq!(local.bucket_index(&id(0b0100)), Some(2));
+        assert_eq!(local.bucket_index(&id(0b0000)), None);
+    }
+
+    #[test]
+    fn closest_sorts_by_xor_distance() {
+        let mut table = RoutingTable::new(id(0));
+        let addr: SocketAddr = "127.0.0.1:2319".parse().unwrap();
+        for byte in [0b0100u8, 0b0001, 0b0010] {
+            table.update(Peer { id: id(byte), addr }, &AlwaysAlive);
+        }
+        let ordered: Vec<u8> = table
+            .closest(&id(0), K)
+            .iter()
+            .map(|p| p.id.0[ID_BYTES - 1])
+            .collect();
+        assert_eq!(ordered, vec![0b0001, 0b0010, 0b0100]);
+    }
+
+    #[test]
+    fn lookup_converges_on_the_target() {
+        // An RPC that always returns the target node itself.
+        struct OneHop {
+            target: NodeId,
+        }
+        impl DhtRpc for OneHop {
+            fn find_node(&self, _peer: &Peer, _target: &NodeId) -> Vec<Peer> {
+                vec![Peer {
+                    id: self.target,
+                    addr: "127.0.0.1:2319".parse().unwrap(),
+                }]
+            }
+        }
+
+        let mut table = RoutingTable::new(id(0));
+        let addr: SocketAddr = "127.0.0.1:2319".parse().unwrap();
+        table.update(Peer { id: id(0b1000), addr }, &AlwaysAlive);
+
+        let target = id(0b0001);
+        let result = lookup(&table, &OneHop { target }, target);
+        assert_eq!(result.first().unwrap().id, target);
+    }
+}
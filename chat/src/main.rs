@@ -1,17 +1,29 @@
 use byteorder::{ByteOrder, NetworkEndian};
+use bytes::BytesMut;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use lazy_static::lazy_static;
+use rand_core::OsRng;
 use std::collections::HashMap;
 use std::env;
 use std::error;
 use std::io::Read;
 use std::io::Write;
 use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU16;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::WebSocket;
+use tungstenite::Message as WsMessage;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+mod dht;
 
 #[derive(Debug)]
 struct Message {
@@ -20,16 +32,42 @@ struct Message {
     content: Vec<u8>,
 }
 
+#[derive(Debug, Clone)]
+struct UserEntry {
+    user_id: u16,
+    nick: Option<String>,
+}
+
 #[derive(Debug)]
 enum Packet {
     UserList,
     Say(Message),
+    Roster(Vec<UserEntry>),
+    Join(UserEntry),
+    Leave(u16),
+    Ping,
+    Pong,
+    /// Inter-server: deliver this message to its `to` user on the receiving
+    /// server, preserving `from` (not a fresh client ingress). The second field
+    /// is a hop budget, decremented on every relay so a stale routing cycle
+    /// cannot bounce a message forever.
+    Forward(Message, u8),
+    /// Inter-server FIND_NODE: the peers known closest to this key.
+    FindNode(dht::NodeId),
+    Nodes(Vec<dht::Peer>),
+    /// Inter-server owner lookup: who currently holds this user.
+    FindOwner(u16),
+    Owner(u16, Option<String>),
+    /// Inter-server announcement that `addr` now holds this user.
+    AnnounceUser(u16, String),
+    /// Client sets (or clears, when empty) its own nickname.
+    SetNick(String),
 }
 
 #[derive(Debug)]
 enum MyError {
     UnknownPacketType,
-    PacketTooLong,
+    DecryptFailed,
 }
 
 impl std::error::Error for MyError {}
@@ -38,15 +76,158 @@ impl std::fmt::Display for MyError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let msg = match self {
             MyError::UnknownPacketType => "unknown packet type",
-            MyError::PacketTooLong => "packet too long",
+            MyError::DecryptFailed => "AEAD tag verification failed",
         };
         write!(f, "{}", msg)
     }
 }
 
+/// One direction of a ChaCha20-Poly1305 channel.
+///
+/// A single 32-byte secret is shared by both directions, so the first nonce
+/// byte carries the direction (0 = client->server, 1 = server->client) and the
+/// last eight bytes hold a per-direction monotonic counter. That guarantees a
+/// (key, nonce) pair is never reused either within or across directions. Any
+/// tag-verification failure is surfaced as an error so the caller drops the
+/// connection.
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    direction: u8,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: [u8; 32], direction: u8) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            direction,
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[0] = self.direction;
+        nonce[4..12].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        *Nonce::from_slice(&nonce)
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.aead
+            .encrypt(&nonce, plaintext)
+            .expect("ChaCha20-Poly1305 encryption never fails")
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let nonce = self.next_nonce();
+        self.aead
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| Box::new(MyError::DecryptFailed) as Box<dyn error::Error>)
+    }
+}
+
+/// The write half of a connection: anything the relay can push a `Packet` to.
+trait PacketSink: Send {
+    fn send(&mut self, packet: &Packet) -> Result<(), Box<dyn error::Error>>;
+}
+
+/// The read half of a connection: anything that yields whole `Packet`s.
+trait PacketSource: Send {
+    fn recv(&mut self) -> Result<Packet, Box<dyn error::Error>>;
+}
+
+/// How often the sweeper pings idle sessions, and how long a session may go
+/// without producing any packet before it is reaped.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Hop budget for a freshly relayed inter-server `Forward`. Bounds how many
+/// times a message may be re-forwarded before it is dropped, so stale directory
+/// entries pointing at each other cannot bounce it forever.
+const MAX_FORWARD_HOPS: u8 = 4;
+
+/// A connected client: the sink the relay writes to, an optional nickname, and
+/// the time we last heard anything from it.
+struct Session {
+    sink: Box<dyn PacketSink>,
+    nick: Option<String>,
+    last_seen: Instant,
+}
+
+/// Whether this process speaks the encrypted (`-e`) transport, consulted by the
+/// federation code so inter-server connections match the cluster's mode.
+static ENCRYPTED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     static ref NEXT_USER_ID: AtomicU16 = AtomicU16::new(1);
-    static ref SESSIONS: Mutex<HashMap<u16, TcpStream>> = Mutex::new(HashMap::new());
+    static ref SESSIONS: Mutex<HashMap<u16, Session>> = Mutex::new(HashMap::new());
+    /// This server's federation address, reachable by peer servers and
+    /// announced into the DHT for every user it holds.
+    static ref SERVER_ADDR: String =
+        env::var("CHAT_ADDR").unwrap_or_else(|_| "127.0.0.1:2318".to_string());
+    /// This node's id in the DHT key space, derived from `SERVER_ADDR`.
+    static ref LOCAL_ID: dht::NodeId = dht::node_id(&SERVER_ADDR);
+    /// k-buckets of known peer servers, seeded from `CHAT_PEERS` and grown as
+    /// FIND_NODE answers come back.
+    static ref ROUTING: Mutex<dht::RoutingTable> = Mutex::new(dht::RoutingTable::new(*LOCAL_ID));
+    /// Directory mapping users to the server currently holding them, consulted
+    /// when a private message targets a user not in the local `SESSIONS`.
+    static ref DIRECTORY: Mutex<dht::Directory> = Mutex::new(dht::Directory::new());
+}
+
+fn read_user_entry(buf: &[u8], offset: usize) -> Result<(UserEntry, usize), Box<dyn error::Error>> {
+    let user_id = NetworkEndian::read_u16(&buf[offset..offset + 2]);
+    let nick_len = buf[offset + 2] as usize;
+    let start = offset + 3;
+    let nick = if nick_len == 0 {
+        None
+    } else {
+        Some(String::from_utf8(buf[start..start + nick_len].to_vec())?)
+    };
+    Ok((UserEntry { user_id, nick }, 3 + nick_len))
+}
+
+fn push_user_entry(dst: &mut Vec<u8>, entry: &UserEntry) {
+    let mut head = [0u8; 2];
+    NetworkEndian::write_u16(&mut head, entry.user_id);
+    dst.extend_from_slice(&head);
+    let nick = entry.nick.as_deref().unwrap_or("");
+    dst.push(nick.len() as u8);
+    dst.extend_from_slice(nick.as_bytes());
+}
+
+/// A length-prefixed UTF-8 string (`u8` length + bytes), used for peer
+/// addresses in the federation packets.
+fn push_str(dst: &mut Vec<u8>, s: &str) {
+    dst.push(s.len() as u8);
+    dst.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(buf: &[u8], offset: usize) -> Result<(String, usize), Box<dyn error::Error>> {
+    let len = buf[offset] as usize;
+    let start = offset + 1;
+    let s = String::from_utf8(buf[start..start + len].to_vec())?;
+    Ok((s, 1 + len))
+}
+
+fn push_peer(dst: &mut Vec<u8>, peer: &dht::Peer) {
+    dst.extend_from_slice(&peer.id.0);
+    push_str(dst, &peer.addr.to_string());
+}
+
+fn read_peer(buf: &[u8], offset: usize) -> Result<(dht::Peer, usize), Box<dyn error::Error>> {
+    let mut id = [0u8; dht::ID_BYTES];
+    id.copy_from_slice(&buf[offset..offset + dht::ID_BYTES]);
+    let (addr, consumed) = read_str(buf, offset + dht::ID_BYTES)?;
+    Ok((
+        dht::Peer {
+            id: dht::NodeId(id),
+            addr: addr.parse()?,
+        },
+        dht::ID_BYTES + consumed,
+    ))
 }
 
 fn decode_packet(buf: &[u8]) -> Result<Packet, Box<dyn error::Error>> {
@@ -58,86 +239,781 @@ fn decode_packet(buf: &[u8]) -> Result<Packet, Box<dyn error::Error>> {
             to: NetworkEndian::read_u16(&buf[5..7]),
             content: (&buf[7..]).into(),
         })),
+        2 => {
+            let mut entries = Vec::new();
+            let mut i = 3;
+            while i < buf.len() {
+                let (entry, consumed) = read_user_entry(buf, i)?;
+                entries.push(entry);
+                i += consumed;
+            }
+            Ok(Packet::Roster(entries))
+        }
+        3 => {
+            let (entry, _) = read_user_entry(buf, 3)?;
+            Ok(Packet::Join(entry))
+        }
+        4 => Ok(Packet::Leave(NetworkEndian::read_u16(&buf[3..5]))),
+        5 => Ok(Packet::Ping),
+        6 => Ok(Packet::Pong),
+        7 => Ok(Packet::Forward(
+            Message {
+                from: NetworkEndian::read_u16(&buf[3..5]),
+                to: NetworkEndian::read_u16(&buf[5..7]),
+                content: (&buf[8..]).into(),
+            },
+            buf[7],
+        )),
+        8 => {
+            let mut id = [0u8; dht::ID_BYTES];
+            id.copy_from_slice(&buf[3..3 + dht::ID_BYTES]);
+            Ok(Packet::FindNode(dht::NodeId(id)))
+        }
+        9 => {
+            let mut peers = Vec::new();
+            let mut i = 3;
+            while i < buf.len() {
+                let (peer, consumed) = read_peer(buf, i)?;
+                peers.push(peer);
+                i += consumed;
+            }
+            Ok(Packet::Nodes(peers))
+        }
+        10 => Ok(Packet::FindOwner(NetworkEndian::read_u16(&buf[3..5]))),
+        11 => {
+            let user_id = NetworkEndian::read_u16(&buf[3..5]);
+            let (addr, _) = read_str(buf, 5)?;
+            let owner = if addr.is_empty() { None } else { Some(addr) };
+            Ok(Packet::Owner(user_id, owner))
+        }
+        12 => {
+            let user_id = NetworkEndian::read_u16(&buf[3..5]);
+            let (addr, _) = read_str(buf, 5)?;
+            Ok(Packet::AnnounceUser(user_id, addr))
+        }
+        13 => {
+            let (nick, _) = read_str(buf, 3)?;
+            Ok(Packet::SetNick(nick))
+        }
         _ => Err(Box::new(MyError::UnknownPacketType)),
     }
 }
 
-fn encode_packet(packet: &Packet, buf: &mut [u8]) -> usize {
-    let (length, packet_type) = match packet {
-        Packet::UserList => (1, 0),
-        Packet::Say(ref message) => {
-            NetworkEndian::write_u16(&mut buf[3..5], message.from);
-            NetworkEndian::write_u16(&mut buf[5..7], message.to);
-            (&mut buf[7..message.content.len() + 7]).copy_from_slice(&message.content);
-            (1 + 2 + 2 + message.content.len() as u16, 1)
+/// Frames `Packet`s with a 2-byte network-endian length prefix.
+///
+/// `decode` is driven off an accumulating buffer: it returns `Ok(None)` when a
+/// whole frame is not yet available and `Ok(Some(packet))` once one is, leaving
+/// any trailing bytes in place for the next call. Both client and server share
+/// this one implementation.
+struct Codec;
+
+impl Codec {
+    fn encode(&self, packet: &Packet, dst: &mut Vec<u8>) {
+        let start = dst.len();
+        dst.extend_from_slice(&[0, 0]); // length placeholder, filled in below
+
+        match packet {
+            Packet::UserList => dst.push(0),
+            Packet::Say(ref message) => {
+                dst.push(1);
+                let mut head = [0u8; 4];
+                NetworkEndian::write_u16(&mut head[0..2], message.from);
+                NetworkEndian::write_u16(&mut head[2..4], message.to);
+                dst.extend_from_slice(&head);
+                dst.extend_from_slice(&message.content);
+            }
+            Packet::Roster(ref entries) => {
+                dst.push(2);
+                for entry in entries {
+                    push_user_entry(dst, entry);
+                }
+            }
+            Packet::Join(ref entry) => {
+                dst.push(3);
+                push_user_entry(dst, entry);
+            }
+            Packet::Leave(user_id) => {
+                dst.push(4);
+                let mut head = [0u8; 2];
+                NetworkEndian::write_u16(&mut head, *user_id);
+                dst.extend_from_slice(&head);
+            }
+            Packet::Ping => dst.push(5),
+            Packet::Pong => dst.push(6),
+            Packet::Forward(ref message, hops) => {
+                dst.push(7);
+                let mut head = [0u8; 4];
+                NetworkEndian::write_u16(&mut head[0..2], message.from);
+                NetworkEndian::write_u16(&mut head[2..4], message.to);
+                dst.extend_from_slice(&head);
+                dst.push(*hops);
+                dst.extend_from_slice(&message.content);
+            }
+            Packet::FindNode(ref target) => {
+                dst.push(8);
+                dst.extend_from_slice(&target.0);
+            }
+            Packet::Nodes(ref peers) => {
+                dst.push(9);
+                for peer in peers {
+                    push_peer(dst, peer);
+                }
+            }
+            Packet::FindOwner(user_id) => {
+                dst.push(10);
+                let mut head = [0u8; 2];
+                NetworkEndian::write_u16(&mut head, *user_id);
+                dst.extend_from_slice(&head);
+            }
+            Packet::Owner(user_id, ref owner) => {
+                dst.push(11);
+                let mut head = [0u8; 2];
+                NetworkEndian::write_u16(&mut head, *user_id);
+                dst.extend_from_slice(&head);
+                push_str(dst, owner.as_deref().unwrap_or(""));
+            }
+            Packet::AnnounceUser(user_id, ref addr) => {
+                dst.push(12);
+                let mut head = [0u8; 2];
+                NetworkEndian::write_u16(&mut head, *user_id);
+                dst.extend_from_slice(&head);
+                push_str(dst, addr);
+            }
+            Packet::SetNick(ref nick) => {
+                dst.push(13);
+                push_str(dst, nick);
+            }
+        }
+
+        let length = (dst.len() - start - 2) as u16;
+        NetworkEndian::write_u16(&mut dst[start..start + 2], length);
+    }
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Packet>, Box<dyn error::Error>> {
+        if src.len() < 2 {
+            return Ok(None);
         }
-    };
+        let length = NetworkEndian::read_u16(&src[0..2]) as usize;
+        if src.len() < 2 + length {
+            return Ok(None);
+        }
+        let frame = src.split_to(2 + length);
+        Ok(Some(decode_packet(&frame)?))
+    }
+}
+
+/// A `TcpStream` plus the framing buffer needed to read whole packets out of a
+/// stream of partial reads, and the inbound cipher for encrypted connections.
+struct Framed {
+    stream: TcpStream,
+    buffer: BytesMut,
+    cipher: Option<Cipher>,
+}
+
+impl Framed {
+    fn new(stream: TcpStream, cipher: Option<Cipher>) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::new(),
+            cipher,
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), Box<dyn error::Error>> {
+        let mut chunk = [0u8; 1024];
+        let n = self.stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(Box::new(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
 
-    NetworkEndian::write_u16(&mut *buf, length);
-    buf[2] = packet_type;
-    (length + 2) as usize
+    /// Read one length-prefixed raw frame body, accumulating partial reads.
+    fn read_raw_frame(&mut self) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        loop {
+            if self.buffer.len() >= 2 {
+                let len = NetworkEndian::read_u16(&self.buffer[0..2]) as usize;
+                if self.buffer.len() >= 2 + len {
+                    let frame = self.buffer.split_to(2 + len);
+                    return Ok(frame[2..].to_vec());
+                }
+            }
+            self.fill()?;
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Packet, Box<dyn error::Error>> {
+        if self.cipher.is_none() {
+            loop {
+                if let Some(packet) = Codec.decode(&mut self.buffer)? {
+                    return Ok(packet);
+                }
+                self.fill()?;
+            }
+        } else {
+            let body = self.read_raw_frame()?;
+            let plaintext = self.cipher.as_mut().unwrap().open(&body)?;
+            let mut framed = BytesMut::from(&plaintext[..]);
+            Codec
+                .decode(&mut framed)?
+                .ok_or_else(|| Box::new(MyError::UnknownPacketType) as Box<dyn error::Error>)
+        }
+    }
 }
 
-fn receive_packet(stream_receive: &mut TcpStream) -> Result<Packet, Box<dyn error::Error>> {
-    let mut buf: Box<[u8; 1024]> = Box::new([0; 1024]);
-    stream_receive.read_exact(&mut buf[0..2])?;
-    let packet_length = NetworkEndian::read_u16(&buf[0..2]);
-    if (packet_length + 2) > 1024 {
-        return Err(Box::new(MyError::PacketTooLong));
+/// TCP read half.
+struct TcpSource(Framed);
+
+impl PacketSource for TcpSource {
+    fn recv(&mut self) -> Result<Packet, Box<dyn error::Error>> {
+        self.0.read_packet()
     }
-    stream_receive.read_exact(&mut buf[2..(packet_length + 2) as usize])?;
+}
 
-    decode_packet(&buf[0..(packet_length + 2) as usize])
+/// TCP write half, optionally encrypting each frame before it hits the wire.
+struct TcpSink {
+    stream: TcpStream,
+    cipher: Option<Cipher>,
+}
+
+impl PacketSink for TcpSink {
+    fn send(&mut self, packet: &Packet) -> Result<(), Box<dyn error::Error>> {
+        let mut plaintext = Vec::new();
+        Codec.encode(packet, &mut plaintext);
+        match &mut self.cipher {
+            None => self.stream.write_all(&plaintext)?,
+            Some(cipher) => {
+                let ciphertext = cipher.seal(&plaintext);
+                let mut head = [0u8; 2];
+                NetworkEndian::write_u16(&mut head, ciphertext.len() as u16);
+                self.stream.write_all(&head)?;
+                self.stream.write_all(&ciphertext)?;
+            }
+        }
+        Ok(())
+    }
 }
 
-fn server_main() {
-    let listerner = TcpListener::bind("0.0.0.0:2319").unwrap();
-    let (tx, rx): (Sender<Message>, Receiver<Message>) = mpsc::channel();
+/// A single `WebSocket` shared by both directions. tungstenite auto-replies to
+/// client Ping/Close control frames by writing on the socket during `read()`,
+/// so the read and write halves must drive the *same* `WebSocket` under one
+/// lock — two state machines over a duplicated fd corrupt the outbound stream.
+type SharedWs = Arc<Mutex<WebSocket<TcpStream>>>;
 
-    thread::spawn(move || {
-        for message in rx.into_iter() {
-            let p = Packet::Say(message);
-            let mut buf: Box<[u8; 1024]> = Box::new([0; 1024]);
-            let packet_length = encode_packet(&p, &mut *buf);
-            let s = SESSIONS.lock().unwrap();
+/// How long a `read()` may block before releasing the lock so a pending write
+/// can get in. The underlying socket is put in read-timeout mode for this.
+const WS_READ_TIMEOUT: Duration = Duration::from_millis(100);
 
-            for (_, mut stream) in &*s {
-                if let Err(error) = stream.write_all(&(*buf)[0..packet_length]) {
-                    println!("ERROR writing to client: {}", error);
+/// WebSocket read half: each binary message carries one length-prefixed frame.
+struct WsSource(SharedWs);
+
+impl PacketSource for WsSource {
+    fn recv(&mut self) -> Result<Packet, Box<dyn error::Error>> {
+        loop {
+            let message = {
+                let mut ws = self.0.lock().unwrap();
+                match ws.read() {
+                    Ok(message) => message,
+                    Err(tungstenite::Error::Io(ref e))
+                        if e.kind() == std::io::ErrorKind::WouldBlock
+                            || e.kind() == std::io::ErrorKind::TimedOut =>
+                    {
+                        // No frame within the timeout: drop the lock so a
+                        // queued write can proceed, then poll again.
+                        drop(ws);
+                        continue;
+                    }
+                    Err(error) => return Err(Box::new(error)),
+                }
+            };
+            match message {
+                WsMessage::Binary(data) => {
+                    let mut framed = BytesMut::from(&data[..]);
+                    return Codec.decode(&mut framed)?.ok_or_else(|| {
+                        Box::new(MyError::UnknownPacketType) as Box<dyn error::Error>
+                    });
+                }
+                WsMessage::Close(_) => {
+                    return Err(Box::new(std::io::Error::from(
+                        std::io::ErrorKind::UnexpectedEof,
+                    )))
                 }
+                // Ping/Pong/Text frames aren't part of the chat protocol.
+                _ => continue,
             }
         }
-    });
+    }
+}
 
-    for stream in listerner.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                let user_id = NEXT_USER_ID.fetch_add(1, Ordering::SeqCst);
+/// WebSocket write half: shares the one `WebSocket` with its `WsSource`, so
+/// writes serialize against the control-frame replies `read()` emits.
+struct WsSink(SharedWs);
+
+impl PacketSink for WsSink {
+    fn send(&mut self, packet: &Packet) -> Result<(), Box<dyn error::Error>> {
+        let mut buf = Vec::new();
+        Codec.encode(packet, &mut buf);
+        self.0.lock().unwrap().send(WsMessage::Binary(buf))?;
+        Ok(())
+    }
+}
+
+/// Blocking read of a single length-prefixed frame, used for the handshake
+/// where no frame has been buffered yet.
+fn read_raw_frame_blocking(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    let len = NetworkEndian::read_u16(&head) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn write_raw_frame_blocking(stream: &mut TcpStream, body: &[u8]) -> std::io::Result<()> {
+    let mut head = [0u8; 2];
+    NetworkEndian::write_u16(&mut head, body.len() as u16);
+    stream.write_all(&head)?;
+    stream.write_all(body)
+}
+
+fn shared_secret(secret: EphemeralSecret, peer: &[u8]) -> Result<[u8; 32], Box<dyn error::Error>> {
+    if peer.len() != 32 {
+        return Err(Box::new(MyError::DecryptFailed));
+    }
+    let mut pk = [0u8; 32];
+    pk.copy_from_slice(peer);
+    Ok(*secret.diffie_hellman(&PublicKey::from(pk)).as_bytes())
+}
+
+/// Client side of the X25519 exchange: send our public key, read the server's.
+fn client_handshake(stream: &mut TcpStream) -> Result<[u8; 32], Box<dyn error::Error>> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    write_raw_frame_blocking(stream, public.as_bytes())?;
+    let server_public = read_raw_frame_blocking(stream)?;
+    shared_secret(secret, &server_public)
+}
+
+/// Server side: read the client's public key, then send ours.
+fn server_handshake(stream: &mut TcpStream) -> Result<[u8; 32], Box<dyn error::Error>> {
+    let client_public = read_raw_frame_blocking(stream)?;
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    write_raw_frame_blocking(stream, public.as_bytes())?;
+    shared_secret(secret, &client_public)
+}
+
+/// Snapshot of the current roster, for replying to `UserList`.
+fn current_roster() -> Vec<UserEntry> {
+    let s = SESSIONS.lock().unwrap();
+    s.iter()
+        .map(|(user_id, session)| UserEntry {
+            user_id: *user_id,
+            nick: session.nick.clone(),
+        })
+        .collect()
+}
+
+/// Register a freshly connected client and drive its read loop until it drops.
+/// This is generic over the transport: TCP and WebSocket share it.
+fn register_and_serve(
+    source: Box<dyn PacketSource>,
+    sink: Box<dyn PacketSink>,
+    tx: Sender<Packet>,
+) {
+    let user_id = NEXT_USER_ID.fetch_add(1, Ordering::SeqCst);
+    {
+        let mut s = SESSIONS.lock().unwrap();
+        s.insert(
+            user_id,
+            Session {
+                sink,
+                nick: None,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+    // Publish our ownership of this user so other federated servers can route
+    // private messages to it: locally, and out to the closest DHT nodes.
+    DIRECTORY
+        .lock()
+        .unwrap()
+        .announce(user_id, SERVER_ADDR.clone());
+    thread::spawn(move || announce_to_network(user_id));
+    // Announce the new user to everyone (including themselves).
+    tx.send(Packet::Join(UserEntry {
+        user_id,
+        nick: None,
+    }))
+    .unwrap();
+
+    thread::spawn(move || serve_connection(user_id, source, tx));
+}
+
+fn serve_connection(user_id: u16, mut source: Box<dyn PacketSource>, tx: Sender<Packet>) {
+    loop {
+        let packet = source.recv();
+        // Any inbound packet (including a Pong) counts as proof of life.
+        if packet.is_ok() {
+            let mut s = SESSIONS.lock().unwrap();
+            if let Some(session) = s.get_mut(&user_id) {
+                session.last_seen = Instant::now();
+            }
+        }
+        match packet {
+            Ok(Packet::UserList) => {
+                let roster = Packet::Roster(current_roster());
+                let mut s = SESSIONS.lock().unwrap();
+                if let Some(session) = s.get_mut(&user_id) {
+                    if let Err(error) = session.sink.send(&roster) {
+                        println!("ERROR writing roster: {}", error);
+                    }
+                }
+            }
+            Ok(Packet::Say(mut message)) => {
+                message.from = user_id;
+                tx.send(Packet::Say(message)).unwrap();
+            }
+            Ok(Packet::Ping) => {
+                let mut s = SESSIONS.lock().unwrap();
+                if let Some(session) = s.get_mut(&user_id) {
+                    if let Err(error) = session.sink.send(&Packet::Pong) {
+                        println!("ERROR writing pong: {}", error);
+                    }
+                }
+            }
+            Ok(Packet::SetNick(nick)) => {
+                let mut s = SESSIONS.lock().unwrap();
+                if let Some(session) = s.get_mut(&user_id) {
+                    session.nick = if nick.is_empty() { None } else { Some(nick) };
+                }
+            }
+            // Roster/Join/Leave/Pong need no further action here.
+            Ok(_) => {}
+            Err(error) => {
                 {
                     let mut s = SESSIONS.lock().unwrap();
-                    s.insert(user_id, stream.try_clone().unwrap());
+                    s.remove(&user_id);
                 }
+                DIRECTORY.lock().unwrap().forget(user_id);
+                tx.send(Packet::Leave(user_id)).unwrap();
+                println!("ERROR receiving packet: {}", error);
+                return;
+            }
+        }
+    }
+}
 
-                let tx = tx.clone();
+/// The relay thread: fan broadcasts out and route private messages.
+fn relay(rx: Receiver<Packet>) {
+    for packet in rx.into_iter() {
+        let target = match &packet {
+            Packet::Say(message) if message.to != 0 => Some(message.to),
+            _ => None,
+        };
 
-                thread::spawn(move || loop {
-                    let packet = receive_packet(&mut stream);
-                    match packet {
-                        Ok(packet) => match packet {
-                            Packet::UserList => {}
-                            Packet::Say(mut message) => {
-                                message.from = user_id;
-                                tx.send(message).unwrap();
+        match target {
+            Some(to) => {
+                let mut s = SESSIONS.lock().unwrap();
+                if let Some(session) = s.get_mut(&to) {
+                    if let Err(error) = session.sink.send(&packet) {
+                        println!("ERROR writing to client: {}", error);
+                    }
+                } else {
+                    // Not ours: ask the DHT which server owns this user and, if
+                    // it is a different node, forward the message there.
+                    drop(s);
+                    match resolve_owner(to) {
+                        Some(addr) if addr != *SERVER_ADDR => {
+                            if let Packet::Say(message) = packet {
+                                forward_to_server(&addr, message, MAX_FORWARD_HOPS);
                             }
-                        },
+                        }
+                        Some(_) => {}
+                        None => println!("no route to user {}", to),
+                    }
+                }
+            }
+            None => {
+                let mut s = SESSIONS.lock().unwrap();
+                for session in s.values_mut() {
+                    if let Err(error) = session.sink.send(&packet) {
+                        println!("ERROR writing to client: {}", error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A one-shot request/response to a peer server over its federation port: open
+/// a connection (running the X25519 handshake when the cluster is encrypted),
+/// send `request`, and return the single reply frame.
+fn federation_request(addr: &str, request: &Packet) -> Option<Packet> {
+    let encrypted = ENCRYPTED.load(Ordering::SeqCst);
+    let mut stream = TcpStream::connect(addr).ok()?;
+    let (out_cipher, in_cipher) = if encrypted {
+        let key = client_handshake(&mut stream).ok()?;
+        (Some(Cipher::new(key, 0)), Some(Cipher::new(key, 1)))
+    } else {
+        (None, None)
+    };
+    let mut sink = TcpSink {
+        stream: stream.try_clone().ok()?,
+        cipher: out_cipher,
+    };
+    sink.send(request).ok()?;
+    Framed::new(stream, in_cipher).read_packet().ok()
+}
+
+/// Forward a message to the federated server that currently owns the target
+/// user, using the inter-server `Forward` frame so the peer delivers it to `to`
+/// without minting a phantom client session. Honours the encrypted transport.
+fn forward_to_server(addr: &str, message: Message, hops: u8) {
+    if federation_request(addr, &Packet::Forward(message, hops)).is_none() {
+        println!("ERROR forwarding to peer server {}", addr);
+    }
+}
+
+/// Deliver a forwarded message to its `to` user locally, or re-forward it to
+/// the owning peer with one hop spent. Dropped once the hop budget is exhausted.
+fn deliver_forwarded(message: Message, hops: u8) {
+    let to = message.to;
+    {
+        let mut s = SESSIONS.lock().unwrap();
+        if let Some(session) = s.get_mut(&to) {
+            if let Err(error) = session.sink.send(&Packet::Say(message)) {
+                println!("ERROR writing forwarded message: {}", error);
+            }
+            return;
+        }
+    }
+    if hops == 0 {
+        println!("dropping forwarded message to {} (hop limit reached)", to);
+        return;
+    }
+    match resolve_owner(to) {
+        Some(addr) if addr != *SERVER_ADDR => forward_to_server(&addr, message, hops - 1),
+        Some(_) => {}
+        None => println!("no route to user {}", to),
+    }
+}
+
+/// The network FIND_NODE / ping RPC backing the iterative DHT `lookup`. Every
+/// answer is folded back into the local routing table.
+struct NetRpc;
+
+impl dht::Pinger for NetRpc {
+    fn ping(&self, peer: &dht::Peer) -> bool {
+        matches!(
+            federation_request(&peer.addr.to_string(), &Packet::Ping),
+            Some(Packet::Pong)
+        )
+    }
+}
+
+impl dht::DhtRpc for NetRpc {
+    fn find_node(&self, peer: &dht::Peer, target: &dht::NodeId) -> Vec<dht::Peer> {
+        match federation_request(&peer.addr.to_string(), &Packet::FindNode(*target)) {
+            Some(Packet::Nodes(peers)) => {
+                let mut table = ROUTING.lock().unwrap();
+                for learned in &peers {
+                    table.update(learned.clone(), &NetRpc);
+                }
+                peers
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Resolve which server owns `to`: prefer the local directory, then fall back
+/// to an iterative DHT lookup, asking the nodes closest to the user's key which
+/// server currently holds it.
+fn resolve_owner(to: u16) -> Option<String> {
+    if let Some(addr) = DIRECTORY.lock().unwrap().owner_of(to) {
+        return Some(addr);
+    }
+    let target = dht::user_key(to);
+    let snapshot = ROUTING.lock().unwrap().clone();
+    for peer in dht::lookup(&snapshot, &NetRpc, target) {
+        if let Some(Packet::Owner(_, Some(addr))) =
+            federation_request(&peer.addr.to_string(), &Packet::FindOwner(to))
+        {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+/// Publish our ownership of `user_id` to the nodes closest to its key so other
+/// servers can route to it without a central hub.
+fn announce_to_network(user_id: u16) {
+    let target = dht::user_key(user_id);
+    let snapshot = ROUTING.lock().unwrap().clone();
+    for peer in dht::lookup(&snapshot, &NetRpc, target) {
+        federation_request(
+            &peer.addr.to_string(),
+            &Packet::AnnounceUser(user_id, SERVER_ADDR.clone()),
+        );
+    }
+}
+
+/// Periodically ping every session and reap any that has gone quiet for longer
+/// than `IDLE_TIMEOUT`, so half-open peers don't linger in `SESSIONS`.
+fn sweeper(tx: Sender<Packet>) {
+    loop {
+        thread::sleep(PING_INTERVAL);
+        let now = Instant::now();
+        let mut reaped = Vec::new();
+        {
+            let mut s = SESSIONS.lock().unwrap();
+            let mut dead = Vec::new();
+            for (user_id, session) in s.iter_mut() {
+                // Short-circuit keeps us from pinging an already-timed-out
+                // session: reap it if it is stale, or if the ping write fails.
+                if now.duration_since(session.last_seen) > IDLE_TIMEOUT
+                    || session.sink.send(&Packet::Ping).is_err()
+                {
+                    dead.push(*user_id);
+                }
+            }
+            for user_id in dead {
+                s.remove(&user_id);
+                reaped.push(user_id);
+            }
+        }
+        for user_id in reaped {
+            DIRECTORY.lock().unwrap().forget(user_id);
+            tx.send(Packet::Leave(user_id)).unwrap();
+        }
+    }
+}
+
+fn tcp_listener(encrypted: bool, tx: Sender<Packet>) {
+    let listener = TcpListener::bind("0.0.0.0:2319").unwrap();
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let (in_cipher, out_cipher) = if encrypted {
+                    match server_handshake(&mut stream) {
+                        Ok(key) => (Some(Cipher::new(key, 0)), Some(Cipher::new(key, 1))),
                         Err(error) => {
-                            let mut s = SESSIONS.lock().unwrap();
-                            s.remove(&user_id);
-                            println!("ERROR receiving packet: {}", error);
-                            return;
+                            println!("ERROR during handshake: {}", error);
+                            continue;
                         }
                     }
+                } else {
+                    (None, None)
+                };
+
+                let write_stream = stream.try_clone().unwrap();
+                let sink = Box::new(TcpSink {
+                    stream: write_stream,
+                    cipher: out_cipher,
                 });
+                let source = Box::new(TcpSource(Framed::new(stream, in_cipher)));
+                register_and_serve(source, sink, tx.clone());
+            }
+            Err(e) => {
+                println!("Error stream: {}", e);
+            }
+        }
+    }
+}
+
+fn ws_listener(tx: Sender<Packet>) {
+    let listener = TcpListener::bind("0.0.0.0:2320").unwrap();
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => match tungstenite::accept(stream) {
+                Ok(ws) => {
+                    // A short read timeout lets the read loop release the shared
+                    // lock periodically so queued writes can get through; a write
+                    // timeout bounds the control-frame replies `read()` emits so a
+                    // stalled client can never pin the lock indefinitely.
+                    if let Err(error) = ws.get_ref().set_read_timeout(Some(WS_READ_TIMEOUT)) {
+                        println!("ERROR setting read timeout: {}", error);
+                        continue;
+                    }
+                    if let Err(error) = ws.get_ref().set_write_timeout(Some(WS_READ_TIMEOUT)) {
+                        println!("ERROR setting write timeout: {}", error);
+                        continue;
+                    }
+                    // Both directions drive the same `WebSocket` under one lock.
+                    let shared: SharedWs = Arc::new(Mutex::new(ws));
+                    let sink = Box::new(WsSink(Arc::clone(&shared)));
+                    let source = Box::new(WsSource(shared));
+                    register_and_serve(source, sink, tx.clone());
+                }
+                Err(error) => {
+                    println!("ERROR during WebSocket upgrade: {}", error);
+                }
+            },
+            Err(e) => {
+                println!("Error stream: {}", e);
+            }
+        }
+    }
+}
+
+/// Serve a single inter-server request on an already-framed connection and
+/// write back one reply. Unlike `serve_connection` this never mints a client
+/// session: `Forward` is delivered to its `to` user verbatim.
+fn serve_federation(mut framed: Framed, mut sink: TcpSink) {
+    let request = match framed.read_packet() {
+        Ok(packet) => packet,
+        Err(error) => {
+            println!("ERROR reading federation request: {}", error);
+            return;
+        }
+    };
+    let response = match request {
+        Packet::FindNode(target) => Packet::Nodes(ROUTING.lock().unwrap().closest(&target, dht::K)),
+        Packet::FindOwner(user_id) => {
+            Packet::Owner(user_id, DIRECTORY.lock().unwrap().owner_of(user_id))
+        }
+        Packet::AnnounceUser(user_id, addr) => {
+            DIRECTORY.lock().unwrap().announce(user_id, addr);
+            Packet::Pong
+        }
+        Packet::Forward(message, hops) => {
+            deliver_forwarded(message, hops);
+            Packet::Pong
+        }
+        _ => Packet::Pong,
+    };
+    if let Err(error) = sink.send(&response) {
+        println!("ERROR writing federation reply: {}", error);
+    }
+}
+
+/// Accept peer-server connections on `SERVER_ADDR` and answer one RPC each.
+fn federation_listener(encrypted: bool) {
+    let listener = TcpListener::bind(SERVER_ADDR.as_str()).unwrap();
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let (in_cipher, out_cipher) = if encrypted {
+                    match server_handshake(&mut stream) {
+                        Ok(key) => (Some(Cipher::new(key, 0)), Some(Cipher::new(key, 1))),
+                        Err(error) => {
+                            println!("ERROR during federation handshake: {}", error);
+                            continue;
+                        }
+                    }
+                } else {
+                    (None, None)
+                };
+                let sink = TcpSink {
+                    stream: stream.try_clone().unwrap(),
+                    cipher: out_cipher,
+                };
+                let framed = Framed::new(stream, in_cipher);
+                thread::spawn(move || serve_federation(framed, sink));
             }
             Err(e) => {
                 println!("Error stream: {}", e);
@@ -146,17 +1022,84 @@ fn server_main() {
     }
 }
 
-fn client_main() {
+/// Seed the routing table from the comma-separated `CHAT_PEERS` bootstrap list.
+fn bootstrap_peers() {
+    let peers = match env::var("CHAT_PEERS") {
+        Ok(peers) => peers,
+        Err(_) => return,
+    };
+    let mut table = ROUTING.lock().unwrap();
+    for addr in peers.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+        match addr.parse() {
+            Ok(socket_addr) => table.update(
+                dht::Peer {
+                    id: dht::node_id(addr),
+                    addr: socket_addr,
+                },
+                &NetRpc,
+            ),
+            Err(error) => println!("ignoring peer {}: {}", addr, error),
+        }
+    }
+}
+
+fn server_main(encrypted: bool) {
+    ENCRYPTED.store(encrypted, Ordering::SeqCst);
+    bootstrap_peers();
+
+    let (tx, rx): (Sender<Packet>, Receiver<Packet>) = mpsc::channel();
+
+    thread::spawn(move || relay(rx));
+
+    let tx_ws = tx.clone();
+    thread::spawn(move || ws_listener(tx_ws));
+
+    let tx_sweep = tx.clone();
+    thread::spawn(move || sweeper(tx_sweep));
+
+    thread::spawn(move || federation_listener(encrypted));
+
+    tcp_listener(encrypted, tx);
+}
+
+fn client_main(encrypted: bool) {
     match TcpStream::connect("localhost:2319") {
         Ok(mut stream) => {
             println!("Connected to server.");
 
-            let mut stream_receive = stream.try_clone().unwrap();
+            let (mut out_cipher, in_cipher) = if encrypted {
+                match client_handshake(&mut stream) {
+                    Ok(key) => (Some(Cipher::new(key, 0)), Some(Cipher::new(key, 1))),
+                    Err(error) => {
+                        println!("ERROR during handshake: {}", error);
+                        return;
+                    }
+                }
+            } else {
+                (None, None)
+            };
+
+            // The sink is shared so both the main loop and the reader (replying
+            // to server pings) serialize through one cipher counter.
+            let sink = Arc::new(Mutex::new(TcpSink {
+                stream: stream.try_clone().unwrap(),
+                cipher: out_cipher.take(),
+            }));
+
+            let mut source = TcpSource(Framed::new(stream.try_clone().unwrap(), in_cipher));
+            let reader_sink = Arc::clone(&sink);
             thread::spawn(move || loop {
-                let packet = receive_packet(&mut stream_receive);
+                let packet = source.recv();
                 match packet {
                     Ok(packet) => match packet {
                         Packet::UserList => {}
+                        Packet::Ping => {
+                            if let Err(error) = reader_sink.lock().unwrap().send(&Packet::Pong) {
+                                println!("ERROR sending pong: {}", error);
+                                return;
+                            }
+                        }
+                        Packet::Pong => {}
                         Packet::Say(message) => {
                             println!(
                                 "[{} to {}: {}]",
@@ -166,6 +1109,20 @@ fn client_main() {
                             );
                             eprint!(">>>");
                         }
+                        Packet::Roster(entries) => {
+                            println!("[users: {}]", format_roster(&entries));
+                            eprint!(">>>");
+                        }
+                        Packet::Join(entry) => {
+                            println!("[user {} joined]", entry.user_id);
+                            eprint!(">>>");
+                        }
+                        Packet::Leave(user_id) => {
+                            println!("[user {} left]", user_id);
+                            eprint!(">>>");
+                        }
+                        // Inter-server frames are never sent to clients.
+                        _ => {}
                     },
                     Err(error) => {
                         println!("ERROR receiving packet: {}", error);
@@ -179,19 +1136,22 @@ fn client_main() {
                 let mut content = String::new();
                 std::io::stdin().read_line(&mut content).unwrap();
                 let content = content.trim();
-                if content.len() == 0 {
+                if content.is_empty() {
                     continue;
                 }
-                let p = Packet::Say(Message {
-                    from: 0,
-                    to: 0,
-                    content: content.as_bytes().to_vec(),
-                });
-
-                let mut buf: Box<[u8; 1024]> = Box::new([0; 1024]);
-                let packet_length = encode_packet(&p, &mut *buf);
+                // `/nick <name>` (or bare `/nick` to clear) sets this client's
+                // nickname; everything else is a chat message.
+                let p = if let Some(nick) = content.strip_prefix("/nick") {
+                    Packet::SetNick(nick.trim().to_string())
+                } else {
+                    Packet::Say(Message {
+                        from: 0,
+                        to: 0,
+                        content: content.as_bytes().to_vec(),
+                    })
+                };
 
-                if let Err(error) = stream.write_all(&(*buf)[0..packet_length]) {
+                if let Err(error) = sink.lock().unwrap().send(&p) {
                     println!("ERROR sending to server: {}", error);
                     return;
                 }
@@ -203,10 +1163,77 @@ fn client_main() {
     }
 }
 
+fn format_roster(entries: &[UserEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| match &entry.nick {
+            Some(nick) => format!("{}({})", entry.user_id, nick),
+            None => entry.user_id.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() == 2 && args[1] == "-s" {
-        server_main();
+    let encrypted = args.iter().any(|arg| arg == "-e");
+    if args.iter().any(|arg| arg == "-s") {
+        server_main(encrypted);
+    }
+    client_main(encrypted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_reassembles_byte_at_a_time_frame() {
+        let codec = Codec;
+        let mut wire = Vec::new();
+        codec.encode(
+            &Packet::Say(Message {
+                from: 7,
+                to: 3,
+                content: b"hi".to_vec(),
+            }),
+            &mut wire,
+        );
+
+        let mut buffer = BytesMut::new();
+        // Feeding every byte but the last must never yield a packet.
+        for &byte in &wire[..wire.len() - 1] {
+            buffer.extend_from_slice(&[byte]);
+            assert!(codec.decode(&mut buffer).unwrap().is_none());
+        }
+        // The final byte completes exactly one frame.
+        buffer.extend_from_slice(&[wire[wire.len() - 1]]);
+        match codec.decode(&mut buffer).unwrap().expect("a complete packet") {
+            Packet::Say(message) => {
+                assert_eq!(message.from, 7);
+                assert_eq!(message.to, 3);
+                assert_eq!(message.content, b"hi");
+            }
+            other => panic!("unexpected packet: {:?}", other),
+        }
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn chacha_roundtrip_and_tamper_detection() {
+        let key = [7u8; 32];
+        let mut sender = Cipher::new(key, 0);
+        let mut receiver = Cipher::new(key, 0);
+
+        let mut first = sender.seal(b"hello");
+        assert_eq!(receiver.open(&first).unwrap(), b"hello");
+        // Counters advance in lockstep, so the second frame round-trips too.
+        let second = sender.seal(b"world");
+        assert_eq!(receiver.open(&second).unwrap(), b"world");
+
+        // A flipped ciphertext byte must fail tag verification.
+        let mut tampered = Cipher::new(key, 0);
+        first[0] ^= 0xff;
+        assert!(tampered.open(&first).is_err());
     }
-    client_main();
 }
@@ -1,4 +1,5 @@
-use std::{fmt::Display, num::ParseIntError};
+use std::fmt::Display;
+use std::num::ParseFloatError;
 
 type Tree = Box<Node>;
 
@@ -8,6 +9,7 @@ enum Op {
     Sub,
     Mul,
     Div,
+    Pow,
 }
 
 impl Display for Op {
@@ -17,18 +19,7 @@ impl Display for Op {
             Op::Sub => write!(f, "-"),
             Op::Mul => write!(f, "*"),
             Op::Div => write!(f, "/"),
-        }
-    }
-}
-
-impl Op {
-    fn from_token(token: &Token) -> Op {
-        match token {
-            Token::ADD => Op::Add,
-            Token::SUB => Op::Sub,
-            Token::MUL => Op::Mul,
-            Token::DIV => Op::Div,
-            _ => panic!("Not Op"),
+            Op::Pow => write!(f, "^"),
         }
     }
 }
@@ -41,33 +32,53 @@ enum Token {
     SUB,
     MUL,
     DIV,
+    POW,
     NUMBER(String),
     END,
 }
 
-impl Token {
-    fn single_char_token(ch: char) -> Self {
-        match ch {
-            '+' => Self::ADD,
-            '-' => Self::SUB,
-            '*' => Self::MUL,
-            '/' => Self::DIV,
-            '(' => Self::LPAR,
-            ')' => Self::RPAR,
-            _ => {
-                panic!("Unknown single char token")
-            }
-        }
-    }
-}
-
 #[derive(Debug)]
 enum Node {
     Par(Box<Node>),
+    Neg(Box<Node>),
     Expr { op: Op, left: Tree, right: Tree },
     Number(String),
 }
 
+/// A failure while turning a token stream into a syntax tree.
+#[derive(Debug)]
+enum ParseError {
+    FactorExpected(Token),
+    RParExpected(Token),
+    ExpectedEnd(Token),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::FactorExpected(token) => write!(f, "Factor expected, found {:?}", token),
+            ParseError::RParExpected(token) => write!(f, "RPAR expected, found {:?}", token),
+            ParseError::ExpectedEnd(token) => write!(f, "end of input expected, found {:?}", token),
+        }
+    }
+}
+
+/// A failure while evaluating a syntax tree.
+#[derive(Debug)]
+enum EvalError {
+    DivisionByZero,
+    InvalidNumber(ParseFloatError),
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::InvalidNumber(error) => write!(f, "invalid number: {}", error),
+        }
+    }
+}
+
 fn tokenize(input: &str) -> Result<Vec<Token>, String> {
     let input = input.as_bytes();
     let mut result = Vec::new();
@@ -75,22 +86,45 @@ fn tokenize(input: &str) -> Result<Vec<Token>, String> {
     while index < input.len() {
         let current_char = input[index] as char;
         match current_char {
-            '(' | ')' | '+' | '-' | '*' | '/' => {
-                result.push(Token::single_char_token(current_char));
-            }
+            '(' => result.push(Token::LPAR),
+            ')' => result.push(Token::RPAR),
+            '+' => result.push(Token::ADD),
+            '-' => result.push(Token::SUB),
+            '*' => result.push(Token::MUL),
+            '/' => result.push(Token::DIV),
+            '^' => result.push(Token::POW),
 
-            '0'..='9' => {
-                let mut num_index = index + 1;
+            '0'..='9' | '.' => {
+                // A floating-point literal: digits with at most one decimal
+                // point and an optional `e`/`E` exponent with an optional sign.
+                let mut num_index = index;
+                let mut seen_dot = false;
+                let mut seen_exp = false;
                 while num_index < input.len() {
                     let ch = input[num_index] as char;
-                    if ch >= '0' && ch <= '9' {
-                        num_index += 1;
-                    } else {
-                        break;
+                    match ch {
+                        '0'..='9' => num_index += 1,
+                        '.' if !seen_dot && !seen_exp => {
+                            seen_dot = true;
+                            num_index += 1;
+                        }
+                        'e' | 'E' if !seen_exp => {
+                            seen_exp = true;
+                            num_index += 1;
+                            if num_index < input.len()
+                                && (input[num_index] == b'+' || input[num_index] == b'-')
+                            {
+                                num_index += 1;
+                            }
+                        }
+                        _ => break,
                     }
                 }
-                let n = Token::NUMBER(String::from_utf8(input[index..num_index].to_vec()).unwrap());
-                result.push(n);
+                let text = String::from_utf8(input[index..num_index].to_vec()).unwrap();
+                if text.parse::<f64>().is_err() {
+                    return Err(format!("Invalid number literal: {}", text));
+                }
+                result.push(Token::NUMBER(text));
                 index = num_index - 1;
             }
             ' ' | '\t' => {
@@ -110,9 +144,11 @@ fn tokenize(input: &str) -> Result<Vec<Token>, String> {
 
 /*
  Expr   := Term Expr1
- Expr1  := '+' Term Expr1 | Empty
- Term   := Factor Term1
- Term1  := '*' Factor Term1 | Empty
+ Expr1  := ('+' | '-') Term Expr1 | Empty
+ Term   := Power Term1
+ Term1  := ('*' | '/') Power Term1 | Empty
+ Power  := Unary ('^' Power)?            // right-associative
+ Unary  := '-' Unary | Factor
  Factor := '(' Expr ')' | Number
 */
 struct Parser<'a> {
@@ -125,72 +161,109 @@ impl<'a> Parser<'a> {
         Self { tokens, current: 0 }
     }
 
-    fn expr(&mut self) -> Tree {
-        let term = self.term();
+    /// Parse the whole token stream, rejecting any tokens left over after
+    /// the top-level expression.
+    fn parse(&mut self) -> Result<Tree, ParseError> {
+        let tree = self.expr()?;
+        if self.tokens[self.current] != Token::END {
+            Err(ParseError::ExpectedEnd(self.tokens[self.current].clone()))
+        } else {
+            Ok(tree)
+        }
+    }
+
+    fn expr(&mut self) -> Result<Tree, ParseError> {
+        let term = self.term()?;
         self.expr1(term)
     }
 
-    fn expr1(&mut self, left: Tree) -> Tree {
-        let token = &self.tokens[self.current];
-        if *token == Token::ADD || *token == Token::SUB {
+    fn expr1(&mut self, left: Tree) -> Result<Tree, ParseError> {
+        let token = self.tokens[self.current].clone();
+        if token == Token::ADD || token == Token::SUB {
             self.current += 1;
-            let term = self.term();
+            let op = if token == Token::ADD { Op::Add } else { Op::Sub };
+            let term = self.term()?;
             let t = Box::new(Node::Expr {
-                op: Op::from_token(token),
-                left: left,
+                op,
+                left,
                 right: term,
             });
             self.expr1(t)
         } else {
-            left
+            Ok(left)
         }
     }
 
-    fn term(&mut self) -> Tree {
-        let factor = self.factor();
-        self.term1(factor)
+    fn term(&mut self) -> Result<Tree, ParseError> {
+        let power = self.power()?;
+        self.term1(power)
     }
 
-    fn term1(&mut self, left: Tree) -> Tree {
-        let token = &self.tokens[self.current];
-        if *token == Token::MUL || *token == Token::DIV {
+    fn term1(&mut self, left: Tree) -> Result<Tree, ParseError> {
+        let token = self.tokens[self.current].clone();
+        if token == Token::MUL || token == Token::DIV {
             self.current += 1;
-            let factor = self.factor();
+            let op = if token == Token::MUL { Op::Mul } else { Op::Div };
+            let power = self.power()?;
             let t = Box::new(Node::Expr {
-                op: Op::from_token(token),
-                left: left,
-                right: factor,
+                op,
+                left,
+                right: power,
             });
             self.term1(t)
         } else {
-            left
+            Ok(left)
+        }
+    }
+
+    fn power(&mut self) -> Result<Tree, ParseError> {
+        let base = self.unary()?;
+        if self.tokens[self.current] == Token::POW {
+            self.current += 1;
+            // Right-associative: the exponent is itself a `Power`.
+            let exponent = self.power()?;
+            Ok(Box::new(Node::Expr {
+                op: Op::Pow,
+                left: base,
+                right: exponent,
+            }))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn unary(&mut self) -> Result<Tree, ParseError> {
+        if self.tokens[self.current] == Token::SUB {
+            self.current += 1;
+            let operand = self.unary()?;
+            Ok(Box::new(Node::Neg(operand)))
+        } else {
+            self.factor()
         }
     }
 
-    fn factor(&mut self) -> Tree {
+    fn factor(&mut self) -> Result<Tree, ParseError> {
         match self.tokens[self.current] {
             Token::LPAR => {
                 self.current += 1;
-                let expr = self.expr();
+                let expr = self.expr()?;
                 if self.tokens[self.current] != Token::RPAR {
-                    panic!("RPAR expected");
+                    Err(ParseError::RParExpected(self.tokens[self.current].clone()))
                 } else {
                     self.current += 1;
-                    Box::new(Node::Par(expr))
+                    Ok(Box::new(Node::Par(expr)))
                 }
             }
             Token::NUMBER(ref num) => {
                 self.current += 1;
-                Box::new(Node::Number(num.clone()))
-            }
-            _ => {
-                panic!("Factor expected")
+                Ok(Box::new(Node::Number(num.clone())))
             }
+            _ => Err(ParseError::FactorExpected(self.tokens[self.current].clone())),
         }
     }
 }
 
-fn eval_tree(tree: &Tree) -> Result<i64, ParseIntError> {
+fn eval_tree(tree: &Tree) -> Result<f64, EvalError> {
     match **tree {
         Node::Expr {
             ref op,
@@ -203,14 +276,22 @@ fn eval_tree(tree: &Tree) -> Result<i64, ParseIntError> {
                 Op::Add => vl + vr,
                 Op::Sub => vl - vr,
                 Op::Mul => vl * vr,
-                Op::Div => vl / vr,
+                Op::Div => {
+                    if vr == 0.0 {
+                        return Err(EvalError::DivisionByZero);
+                    }
+                    vl / vr
+                }
+                Op::Pow => vl.powf(vr),
             };
             Ok(v)
         }
 
+        Node::Neg(ref expr) => Ok(-eval_tree(expr)?),
+
         Node::Par(ref expr) => eval_tree(expr),
 
-        Node::Number(ref num) => num.parse::<i64>(),
+        Node::Number(ref num) => num.parse::<f64>().map_err(EvalError::InvalidNumber),
     }
 }
 
@@ -226,6 +307,11 @@ fn print_tree(tree: &Tree) {
             print_tree(right);
         }
 
+        Node::Neg(ref expr) => {
+            print!("-");
+            print_tree(expr);
+        }
+
         Node::Par(ref expr) => {
             print!("(");
             print_tree(expr);
@@ -240,11 +326,77 @@ fn print_tree(tree: &Tree) {
 
 fn main() {
     let input = "(1+2-3)+4*5 -9 /8*7+((6+7))";
-    let tokens = tokenize(input).unwrap();
+    let tokens = match tokenize(input) {
+        Ok(tokens) => tokens,
+        Err(error) => {
+            println!("tokenize error: {}", error);
+            return;
+        }
+    };
     let mut p = Parser::new(&tokens[..]);
-    let e = p.expr();
-    println!("{:?}", p.tokens[p.current]);
-    print_tree(&e);
-    println!("");
-    println!("{}", eval_tree(&e).unwrap());
+    match p.parse() {
+        Ok(e) => {
+            print_tree(&e);
+            println!();
+            match eval_tree(&e) {
+                Ok(value) => println!("{}", value),
+                Err(error) => println!("eval error: {}", error),
+            }
+        }
+        Err(error) => println!("parse error: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(input: &str) -> Result<f64, String> {
+        let tokens = tokenize(input)?;
+        let mut p = Parser::new(&tokens);
+        let tree = p.parse().map_err(|e| e.to_string())?;
+        eval_tree(&tree).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(eval("2+3*4").unwrap(), 14.0);
+    }
+
+    #[test]
+    fn exponentiation_is_right_associative() {
+        assert_eq!(eval("2^3^2").unwrap(), 512.0);
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-3").unwrap(), -3.0);
+        assert_eq!(eval("-(1+2)").unwrap(), -3.0);
+    }
+
+    #[test]
+    fn floating_point_literals() {
+        assert_eq!(eval("1.5e1 + .5").unwrap(), 15.5);
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert!(eval("1/0").is_err());
+    }
+
+    #[test]
+    fn missing_paren_is_an_error() {
+        assert!(eval("(1+2").is_err());
+    }
+
+    #[test]
+    fn dangling_operator_is_an_error() {
+        assert!(eval("1+").is_err());
+    }
+
+    #[test]
+    fn trailing_garbage_is_an_error() {
+        assert!(eval("1 2").is_err());
+        assert!(eval("(1+2)3").is_err());
+    }
 }